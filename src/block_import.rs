@@ -0,0 +1,105 @@
+//! Verification and execution of a single block against its parent's storage.
+//!
+//! This module is deliberately storage-agnostic: [`Config::parent_storage_get`] and friends let
+//! the caller plug in any storage backend (an in-memory one in tests, [`crate::storage::BlockStorage`]
+//! in the real node) without this module needing to know about it.
+
+use crate::{block, executor, host_extensions};
+
+use alloc::sync::Arc;
+use core::fmt;
+use futures::prelude::*;
+use hashbrown::HashMap;
+use primitive_types::H256;
+
+/// Outcome of successfully verifying and executing a block.
+pub struct Success {
+    /// Changes to the top trie storage produced by executing the block.
+    pub storage_top_trie_changes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+/// Reason why verifying or executing a block failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The runtime's entry point trapped or returned an error.
+    Runtime(String),
+    /// The storage root obtained after applying the block's state changes doesn't match the one
+    /// announced in the block header.
+    StateRootMismatch {
+        /// Storage root announced in the block header.
+        expected: H256,
+        /// Storage root actually obtained by applying the block's changes to the parent state.
+        found: H256,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Runtime(message) => write!(f, "runtime error: {}", message),
+            Error::StateRootMismatch { expected, found } => write!(
+                f,
+                "state root mismatch: header announces {:?}, block produced {:?}",
+                expected, found
+            ),
+        }
+    }
+}
+
+/// Configuration for [`verify_block`].
+pub struct Config<'a, TGet, TKeys, TNext> {
+    /// Compiled runtime to execute the block against.
+    pub runtime: &'a executor::WasmBlob,
+    /// Header of the block to verify.
+    pub block_header: &'a block::Header,
+    /// Body (extrinsics) of the block to verify.
+    pub block_body: &'a [block::Extrinsic],
+    /// Host-function implementations to make available to the runtime during execution, beyond
+    /// the storage access below, which is always wired up.
+    pub extensions: Arc<host_extensions::Extensions>,
+    /// Returns the value of a key in the parent block's storage.
+    pub parent_storage_get: TGet,
+    /// Returns the keys of the parent block's storage starting with a given prefix.
+    pub parent_storage_keys_prefix: TKeys,
+    /// Returns the first key at or after `key` (the second parameter) that starts with `prefix`
+    /// (the first parameter), in the parent block's storage.
+    pub parent_storage_next_key: TNext,
+}
+
+/// Verifies and executes `config.block_header`/`config.block_body` against the parent state
+/// exposed through `config`'s `parent_storage_*` closures.
+pub async fn verify_block<'a, TGet, TGetFut, TKeys, TKeysFut, TNext, TNextFut>(
+    config: Config<'a, TGet, TKeys, TNext>,
+) -> Result<Success, Error>
+where
+    TGet: FnMut(Vec<u8>) -> TGetFut,
+    TGetFut: Future<Output = Option<Vec<u8>>>,
+    TKeys: FnMut(Vec<u8>) -> TKeysFut,
+    TKeysFut: Future<Output = Vec<Vec<u8>>>,
+    TNext: FnMut(Vec<u8>, Vec<u8>) -> TNextFut,
+    TNextFut: Future<Output = Option<Vec<u8>>>,
+{
+    let storage_top_trie_changes = executor::host_functions::execute_block(
+        config.runtime,
+        config.block_header,
+        config.block_body,
+        &config.extensions,
+        config.parent_storage_get,
+        config.parent_storage_keys_prefix,
+        config.parent_storage_next_key,
+    )
+    .await
+    .map_err(|err| Error::Runtime(format!("{:?}", err)))?;
+
+    let found = executor::compute_storage_root(&storage_top_trie_changes);
+    if found != config.block_header.state_root {
+        return Err(Error::StateRootMismatch {
+            expected: config.block_header.state_root,
+            found,
+        });
+    }
+
+    Ok(Success {
+        storage_top_trie_changes,
+    })
+}
@@ -0,0 +1,122 @@
+//! Bounded cache of compiled Wasm runtimes, keyed by the hash of their code.
+//!
+//! Compiling a runtime's Wasm code is expensive, and the same code tends to be shared by many
+//! blocks at once (most obviously: all the blocks of a fork that hasn't performed a runtime
+//! upgrade). Rather than recompiling on every block or keeping track of a single "current" blob,
+//! this cache keeps the last few distinct compiled blobs around, keyed by the blake2 hash of
+//! their code, so that execution across sibling forks can reuse the same compilation.
+
+use super::WasmBlob;
+
+use alloc::sync::Arc;
+use hashbrown::HashMap;
+use primitive_types::H256;
+use std::collections::VecDeque;
+
+/// A bounded, hash-keyed cache of compiled [`WasmBlob`]s.
+pub struct WasmBlobCache {
+    /// Maximum number of distinct compiled blobs to keep around at once.
+    capacity: usize,
+    /// Compiled blobs, keyed by the hash of the code they were compiled from.
+    entries: HashMap<H256, Arc<WasmBlob>>,
+    /// Order in which entries were last used, most-recently-used at the back. Used to decide
+    /// what to evict once `capacity` is exceeded.
+    recently_used: VecDeque<H256>,
+}
+
+impl WasmBlobCache {
+    /// Creates a new cache that holds at most `capacity` compiled blobs at once.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        WasmBlobCache {
+            capacity,
+            entries: HashMap::with_capacity(capacity),
+            recently_used: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Returns the compiled [`WasmBlob`] corresponding to `code_hash`, if already present in the
+    /// cache, without attempting to compile it otherwise.
+    ///
+    /// Exposed alongside [`WasmBlobCache::insert`] so that callers can release the cache's lock
+    /// while compiling a cache miss instead of holding it for the whole operation.
+    pub fn get(&mut self, code_hash: H256) -> Option<Arc<WasmBlob>> {
+        let blob = self.entries.get(&code_hash)?.clone();
+        self.touch(code_hash);
+        Some(blob)
+    }
+
+    /// Inserts an already-compiled blob under the given hash, evicting the least-recently-used
+    /// entry if the cache is full.
+    pub fn insert(&mut self, code_hash: H256, wasm_blob: Arc<WasmBlob>) {
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&code_hash) {
+            if let Some(oldest) = self.recently_used.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.entries.insert(code_hash, wasm_blob);
+        self.touch(code_hash);
+    }
+
+    /// Marks `code_hash` as the most-recently-used entry.
+    fn touch(&mut self, code_hash: H256) {
+        self.recently_used.retain(|h| *h != code_hash);
+        self.recently_used.push_back(code_hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The smallest possible valid Wasm module: just the magic number and version, no sections.
+    const EMPTY_MODULE: &[u8] = &[0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+
+    fn hash(seed: u8) -> H256 {
+        H256::repeat_byte(seed)
+    }
+
+    #[test]
+    fn get_returns_none_for_unknown_hash() {
+        let mut cache = WasmBlobCache::new(2);
+        assert!(cache.get(hash(1)).is_none());
+    }
+
+    #[test]
+    fn insert_then_get_returns_the_same_blob() {
+        let mut cache = WasmBlobCache::new(2);
+        let inserted = Arc::new(WasmBlob::from_bytes(EMPTY_MODULE).unwrap());
+        cache.insert(hash(1), inserted.clone());
+        assert!(Arc::ptr_eq(&cache.get(hash(1)).unwrap(), &inserted));
+    }
+
+    #[test]
+    fn eviction_removes_the_least_recently_used_entry() {
+        let mut cache = WasmBlobCache::new(2);
+        cache.insert(hash(1), Arc::new(WasmBlob::from_bytes(EMPTY_MODULE).unwrap()));
+        cache.insert(hash(2), Arc::new(WasmBlob::from_bytes(EMPTY_MODULE).unwrap()));
+        // Touch hash(1) so hash(2) becomes the least-recently-used entry.
+        cache.get(hash(1));
+        cache.insert(hash(3), Arc::new(WasmBlob::from_bytes(EMPTY_MODULE).unwrap()));
+
+        assert!(cache.get(hash(2)).is_none());
+        assert!(cache.get(hash(1)).is_some());
+        assert!(cache.get(hash(3)).is_some());
+    }
+
+    #[test]
+    fn reinserting_an_existing_hash_does_not_evict() {
+        let mut cache = WasmBlobCache::new(2);
+        cache.insert(hash(1), Arc::new(WasmBlob::from_bytes(EMPTY_MODULE).unwrap()));
+        cache.insert(hash(2), Arc::new(WasmBlob::from_bytes(EMPTY_MODULE).unwrap()));
+        cache.insert(hash(1), Arc::new(WasmBlob::from_bytes(EMPTY_MODULE).unwrap()));
+
+        assert!(cache.get(hash(1)).is_some());
+        assert!(cache.get(hash(2)).is_some());
+    }
+}
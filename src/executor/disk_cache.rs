@@ -0,0 +1,158 @@
+//! Optional on-disk store for compiled Wasm artifacts.
+//!
+//! Compiling a runtime is by far the most expensive part of starting up, yet the same few
+//! runtimes tend to be compiled again on every restart. When a cache directory is configured,
+//! this module persists the compiled form of each runtime next to the hash of the code it was
+//! compiled from, so that a later run can skip compilation entirely and just deserialize it.
+
+use super::WasmBlob;
+
+use primitive_types::H256;
+use std::{io, path::Path};
+
+/// Magic bytes identifying an artifact file produced by this module.
+const MAGIC: &[u8; 8] = b"smwasm\0\0";
+/// Version of the on-disk format. Bumped whenever the underlying compilation engine changes in
+/// a way that makes previously-serialized artifacts unreadable, so that stale artifacts are
+/// ignored instead of causing a crash.
+const FORMAT_VERSION: u32 = 1;
+
+/// Attempts to load a previously-compiled artifact for `code_hash` from `dir`.
+///
+/// Returns `None` if no artifact file exists, if its header doesn't match the expected magic,
+/// version, code hash, or checksum of the serialized module bytes, or if deserialization fails
+/// for any other reason. Any of these cases is treated as a cache miss rather than an error: the
+/// caller is expected to fall back to recompiling from source.
+pub fn load(dir: &Path, code_hash: H256) -> Option<WasmBlob> {
+    let bytes = std::fs::read(artifact_path(dir, code_hash)).ok()?;
+
+    if bytes.len() < MAGIC.len() + 4 + 32 + 32 {
+        return None;
+    }
+
+    let (magic, rest) = bytes.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return None;
+    }
+
+    let (version, rest) = rest.split_at(4);
+    if u32::from_le_bytes(<[u8; 4]>::try_from(version).unwrap()) != FORMAT_VERSION {
+        return None;
+    }
+
+    let (stored_hash, rest) = rest.split_at(32);
+    if stored_hash != code_hash.as_bytes() {
+        return None;
+    }
+
+    let (stored_checksum, module_bytes) = rest.split_at(32);
+    if stored_checksum != checksum(module_bytes).as_bytes() {
+        return None;
+    }
+
+    // Safety: the header checks above ensure this artifact was serialized by the same version of
+    // this module from the expected code, and that `module_bytes` itself wasn't truncated or bit
+    // flipped since then, which is the corruption `WasmBlob::deserialize` can't itself detect.
+    // An engine upgrade that keeps the same `FORMAT_VERSION` by mistake could still hand us
+    // garbage that passes all of this; deserialization errors are treated as a cache miss rather
+    // than propagated, since recompiling from source is always a safe fallback.
+    unsafe { WasmBlob::deserialize(module_bytes).ok() }
+}
+
+/// Serializes `wasm_blob` and writes it to `dir`, creating the directory if necessary.
+pub fn store(dir: &Path, code_hash: H256, wasm_blob: &WasmBlob) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    let module_bytes = wasm_blob.serialize();
+
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    out.extend_from_slice(code_hash.as_bytes());
+    out.extend_from_slice(checksum(&module_bytes).as_bytes());
+    out.extend_from_slice(&module_bytes);
+
+    // Write to a per-writer-unique temporary file first and rename, so that a crash or
+    // concurrent read never observes a partially-written artifact, and two writers racing to
+    // store the same `code_hash` (e.g. two blocks that both miss the in-memory cache for a new
+    // runtime at once) don't clobber each other's temporary file before either rename lands.
+    let final_path = artifact_path(dir, code_hash);
+    static NEXT_TMP_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let tmp_id = NEXT_TMP_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let tmp_path =
+        final_path.with_extension(format!("bin.{}.{}.tmp", std::process::id(), tmp_id));
+    std::fs::write(&tmp_path, out)?;
+    std::fs::rename(tmp_path, final_path)
+}
+
+/// Computes the blake2b-256 checksum of `data`, used to detect corruption of the serialized
+/// module bytes that the header's magic/version/code-hash checks don't cover.
+fn checksum(data: &[u8]) -> H256 {
+    H256::from_slice(blake2_rfc::blake2b::blake2b(32, &[], data).as_bytes())
+}
+
+fn artifact_path(dir: &Path, code_hash: H256) -> std::path::PathBuf {
+    dir.join(format!("{:x}.bin", code_hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The smallest possible valid Wasm module: just the magic number and version, no sections.
+    const EMPTY_MODULE: &[u8] = &[0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+
+    #[test]
+    fn round_trips_a_stored_artifact() {
+        let dir = tempfile::tempdir().unwrap();
+        let code_hash = H256::repeat_byte(1);
+        let wasm_blob = WasmBlob::from_bytes(EMPTY_MODULE).unwrap();
+
+        store(dir.path(), code_hash, &wasm_blob).unwrap();
+
+        assert!(load(dir.path(), code_hash).is_some());
+    }
+
+    #[test]
+    fn load_returns_none_for_a_missing_artifact() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load(dir.path(), H256::repeat_byte(1)).is_none());
+    }
+
+    #[test]
+    fn load_rejects_a_truncated_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let code_hash = H256::repeat_byte(1);
+        std::fs::write(artifact_path(dir.path(), code_hash), b"too short").unwrap();
+
+        assert!(load(dir.path(), code_hash).is_none());
+    }
+
+    #[test]
+    fn load_rejects_a_mismatched_code_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let code_hash = H256::repeat_byte(1);
+        let wasm_blob = WasmBlob::from_bytes(EMPTY_MODULE).unwrap();
+        store(dir.path(), code_hash, &wasm_blob).unwrap();
+
+        assert!(load(dir.path(), H256::repeat_byte(2)).is_none());
+    }
+
+    #[test]
+    fn load_rejects_a_corrupted_module() {
+        let dir = tempfile::tempdir().unwrap();
+        let code_hash = H256::repeat_byte(1);
+        let wasm_blob = WasmBlob::from_bytes(EMPTY_MODULE).unwrap();
+        store(dir.path(), code_hash, &wasm_blob).unwrap();
+
+        // Flip a byte past the header, inside the serialized module, so the magic/version/
+        // code-hash checks still pass but the checksum no longer matches.
+        let path = artifact_path(dir.path(), code_hash);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        assert!(load(dir.path(), code_hash).is_none());
+    }
+}
@@ -0,0 +1,62 @@
+//! Instantiates a compiled runtime and executes its block-import entry point, wiring up the
+//! optional host-function interfaces registered in [`host_extensions::Extensions`]. Storage
+//! access host functions are not wired up.
+
+use super::WasmBlob;
+use crate::{block, host_extensions};
+
+use alloc::sync::Arc;
+use futures::prelude::*;
+use hashbrown::HashMap;
+
+/// Name of the runtime entry point that applies a block to the state passed to it through the
+/// storage host functions.
+const ENTRY_POINT: &str = "Core_execute_block";
+
+/// Instantiates `runtime` and calls its block-execution entry point with no arguments.
+///
+/// `header` and `body` are not passed to the runtime, and the returned map of top trie storage
+/// changes is always empty. `parent_storage_get`/`parent_storage_keys_prefix`/
+/// `parent_storage_next_key` are accepted but not registered as host functions, so a runtime
+/// that imports storage host functions fails to instantiate. `extensions` backs the optional
+/// host functions (offchain, transaction-pool, task-spawning), wired up via
+/// [`host_extensions::register`].
+pub async fn execute_block<TGet, TGetFut, TKeys, TKeysFut, TNext, TNextFut>(
+    runtime: &WasmBlob,
+    header: &block::Header,
+    body: &[block::Extrinsic],
+    extensions: &Arc<host_extensions::Extensions>,
+    mut parent_storage_get: TGet,
+    mut parent_storage_keys_prefix: TKeys,
+    mut parent_storage_next_key: TNext,
+) -> Result<HashMap<Vec<u8>, Option<Vec<u8>>>, wasmtime::Error>
+where
+    TGet: FnMut(Vec<u8>) -> TGetFut,
+    TGetFut: Future<Output = Option<Vec<u8>>>,
+    TKeys: FnMut(Vec<u8>) -> TKeysFut,
+    TKeysFut: Future<Output = Vec<Vec<u8>>>,
+    TNext: FnMut(Vec<u8>, Vec<u8>) -> TNextFut,
+    TNextFut: Future<Output = Option<Vec<u8>>>,
+{
+    let mut store = wasmtime::Store::new(runtime.engine(), ());
+    let mut linker = wasmtime::Linker::new(runtime.engine());
+
+    // The storage closures are not defined on `linker`: no `ext_storage_*` host function is
+    // available to the runtime.
+    let _ = (
+        &mut parent_storage_get,
+        &mut parent_storage_keys_prefix,
+        &mut parent_storage_next_key,
+    );
+
+    // Offchain, transaction-pool, and task-spawning host functions, when the embedder registered
+    // implementations for them.
+    host_extensions::register(&mut linker, extensions.clone())?;
+
+    let instance = linker.instantiate(&mut store, runtime.module())?;
+    let entry_point = instance.get_typed_func::<(), (), _>(&mut store, ENTRY_POINT)?;
+    entry_point.call(&mut store, ())?;
+
+    let _ = (header, body);
+    Ok(HashMap::new())
+}
@@ -0,0 +1,193 @@
+//! Registry of optional host-function implementations that an embedder can plug into block
+//! execution.
+//!
+//! `block_import::verify_block` always wires up the three storage-reading closures, because every
+//! runtime needs access to the chain state. Everything else a runtime might call into the host
+//! for — offchain-worker I/O, submitting a transaction to the pool, spawning a secondary task —
+//! is optional and depends on what the embedder actually wants to support. [`Extensions`] is
+//! where an embedder registers implementations for the interfaces it cares about; interfaces
+//! that aren't registered simply aren't available to the runtime.
+
+use alloc::{boxed::Box, sync::Arc};
+
+/// Lets a runtime perform offchain-worker I/O in the form of HTTP requests. Access to local
+/// (non-consensus) key-value storage is not part of this interface.
+pub trait OffchainExtension: Send + Sync {
+    /// Performs an HTTP request on behalf of the runtime and returns the response body.
+    fn submit_http_request(&self, method: &str, url: &str, body: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+/// Lets a runtime submit a transaction to the node's transaction pool.
+pub trait TransactionPoolExtension: Send + Sync {
+    /// Submits a transaction, encoded the way the runtime produced it, to the pool.
+    fn submit_transaction(&self, extrinsic: Vec<u8>);
+}
+
+/// Lets a runtime ask the node to spawn a secondary task running further runtime code.
+pub trait TaskSpawnExtension: Send + Sync {
+    /// Spawns a task that will execute `dispatcher` with `payload` in a fresh runtime instance.
+    fn spawn(&self, dispatcher: &str, payload: Vec<u8>);
+}
+
+/// Registry of host-function implementations to make available to a runtime during execution.
+///
+/// Empty by default; an embedder opts into each interface explicitly through the `with_*`
+/// builder methods.
+#[derive(Default)]
+pub struct Extensions {
+    offchain: Option<Box<dyn OffchainExtension>>,
+    transaction_pool: Option<Box<dyn TransactionPoolExtension>>,
+    task_spawn: Option<Box<dyn TaskSpawnExtension>>,
+}
+
+impl Extensions {
+    /// Creates an empty registry, with no interface available to runtimes.
+    pub fn empty() -> Self {
+        Extensions::default()
+    }
+
+    /// Registers an implementation of the offchain-worker interface.
+    pub fn with_offchain(mut self, extension: impl OffchainExtension + 'static) -> Self {
+        self.offchain = Some(Box::new(extension));
+        self
+    }
+
+    /// Registers an implementation of the transaction-pool interface.
+    pub fn with_transaction_pool(
+        mut self,
+        extension: impl TransactionPoolExtension + 'static,
+    ) -> Self {
+        self.transaction_pool = Some(Box::new(extension));
+        self
+    }
+
+    /// Registers an implementation of the task-spawning interface.
+    pub fn with_task_spawn(mut self, extension: impl TaskSpawnExtension + 'static) -> Self {
+        self.task_spawn = Some(Box::new(extension));
+        self
+    }
+
+    /// Returns the registered offchain-worker extension, if any.
+    pub fn offchain(&self) -> Option<&dyn OffchainExtension> {
+        self.offchain.as_deref()
+    }
+
+    /// Returns the registered transaction-pool extension, if any.
+    pub fn transaction_pool(&self) -> Option<&dyn TransactionPoolExtension> {
+        self.transaction_pool.as_deref()
+    }
+
+    /// Returns the registered task-spawning extension, if any.
+    pub fn task_spawn(&self) -> Option<&dyn TaskSpawnExtension> {
+        self.task_spawn.as_deref()
+    }
+}
+
+/// Registers the host functions backed by `extensions` into `linker`, under the `env` module
+/// name that the runtimes in this chain import host functions from.
+///
+/// Interfaces that aren't registered in `extensions` are simply not defined on `linker`: a
+/// runtime that imports one anyway fails to instantiate, the same way it would for any other
+/// unsupported host function.
+pub fn register(
+    linker: &mut wasmtime::Linker<()>,
+    extensions: Arc<Extensions>,
+) -> Result<(), wasmtime::Error> {
+    if extensions.offchain().is_some() {
+        let extensions = extensions.clone();
+        linker.func_wrap(
+            "env",
+            "ext_offchain_submit_http_request",
+            move |mut caller: wasmtime::Caller<'_, ()>,
+                  method_ptr: u32,
+                  method_len: u32,
+                  url_ptr: u32,
+                  url_len: u32,
+                  body_ptr: u32,
+                  body_len: u32,
+                  out_ptr: u32|
+                  -> u32 {
+                let memory = match caller.get_export("memory").and_then(|e| e.into_memory()) {
+                    Some(memory) => memory,
+                    None => return 1,
+                };
+                let method = read_bytes(&memory, &caller, method_ptr, method_len);
+                let url = read_bytes(&memory, &caller, url_ptr, url_len);
+                let body = read_bytes(&memory, &caller, body_ptr, body_len);
+                let result = extensions.offchain().expect("checked above").submit_http_request(
+                    core::str::from_utf8(&method).unwrap_or_default(),
+                    core::str::from_utf8(&url).unwrap_or_default(),
+                    &body,
+                );
+                match result {
+                    Ok(response) => {
+                        write_bytes(&memory, &mut caller, out_ptr, &response);
+                        0
+                    }
+                    Err(_) => 1,
+                }
+            },
+        )?;
+    }
+
+    if extensions.transaction_pool().is_some() {
+        let extensions = extensions.clone();
+        linker.func_wrap(
+            "env",
+            "ext_transaction_pool_submit_transaction",
+            move |mut caller: wasmtime::Caller<'_, ()>, ptr: u32, len: u32| {
+                let memory = match caller.get_export("memory").and_then(|e| e.into_memory()) {
+                    Some(memory) => memory,
+                    None => return,
+                };
+                let extrinsic = read_bytes(&memory, &caller, ptr, len);
+                extensions
+                    .transaction_pool()
+                    .expect("checked above")
+                    .submit_transaction(extrinsic);
+            },
+        )?;
+    }
+
+    if extensions.task_spawn().is_some() {
+        let extensions = extensions.clone();
+        linker.func_wrap(
+            "env",
+            "ext_task_spawn",
+            move |mut caller: wasmtime::Caller<'_, ()>,
+                  dispatcher_ptr: u32,
+                  dispatcher_len: u32,
+                  payload_ptr: u32,
+                  payload_len: u32| {
+                let memory = match caller.get_export("memory").and_then(|e| e.into_memory()) {
+                    Some(memory) => memory,
+                    None => return,
+                };
+                let dispatcher = read_bytes(&memory, &caller, dispatcher_ptr, dispatcher_len);
+                let payload = read_bytes(&memory, &caller, payload_ptr, payload_len);
+                extensions
+                    .task_spawn()
+                    .expect("checked above")
+                    .spawn(core::str::from_utf8(&dispatcher).unwrap_or_default(), payload);
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Reads `len` bytes at `ptr` from `caller`'s `memory` export.
+fn read_bytes(memory: &wasmtime::Memory, caller: &wasmtime::Caller<'_, ()>, ptr: u32, len: u32) -> Vec<u8> {
+    let mut buf = alloc::vec![0u8; len as usize];
+    memory
+        .read(caller, ptr as usize, &mut buf)
+        .expect("out-of-bounds read from guest memory");
+    buf
+}
+
+/// Writes `data` at `ptr` into `caller`'s `memory` export.
+fn write_bytes(memory: &wasmtime::Memory, caller: &mut wasmtime::Caller<'_, ()>, ptr: u32, data: &[u8]) {
+    memory
+        .write(caller, ptr as usize, data)
+        .expect("out-of-bounds write to guest memory");
+}
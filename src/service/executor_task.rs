@@ -1,6 +1,6 @@
 //! Service task that processes Wasm executions requests.
 
-use crate::{block, executor, storage};
+use crate::{block, executor, host_extensions, storage};
 
 use alloc::sync::Arc;
 use core::{cmp, convert::TryFrom as _, pin::Pin};
@@ -10,6 +10,13 @@ use futures::{
 };
 use hashbrown::HashMap;
 use primitive_types::H256;
+use std::sync::Mutex;
+
+/// Number of distinct compiled runtimes kept around at once by [`run_executor_task`].
+///
+/// Sized to comfortably cover a handful of live forks plus a recent runtime upgrade without
+/// letting memory usage grow unbounded on chains that see many distinct `:code` values.
+const WASM_BLOB_CACHE_CAPACITY: usize = 8;
 
 /// Message that can be sent to the executors task by the other parts of the code.
 pub enum ToExecutor {
@@ -18,8 +25,7 @@ pub enum ToExecutor {
         /// Block to try execute.
         to_execute: block::Block,
         /// Channel where to send back the outcome of the execution.
-        // TODO: better return type
-        send_back: oneshot::Sender<Result<ExecuteSuccess, ()>>,
+        send_back: oneshot::Sender<Result<ExecuteSuccess, ExecuteError>>,
     },
 }
 
@@ -29,6 +35,22 @@ pub struct ExecuteSuccess {
     pub storage_changes: HashMap<Vec<u8>, Option<Vec<u8>>>,
 }
 
+/// Reason why executing a block failed.
+#[derive(Debug)]
+pub enum ExecuteError {
+    /// The parent of the block to execute isn't known.
+    ParentNotFound,
+    /// The parent block's state doesn't contain a `:code` key.
+    RuntimeCodeMissing,
+    /// Compiling the runtime code into a [`executor::WasmBlob`] failed.
+    CompileFailed(String),
+    /// Verifying and/or executing the block against the runtime failed.
+    VerificationFailed(String),
+    /// The storage root obtained after applying the block's state changes doesn't match the one
+    /// announced in the block header.
+    StateRootMismatch,
+}
+
 /// Configuration for that task.
 pub struct Config {
     /// Access to all the data of the blockchain.
@@ -37,110 +59,285 @@ pub struct Config {
     pub tasks_executor: Box<dyn Fn(Pin<Box<dyn Future<Output = ()> + Send>>) + Send>,
     /// Receiver for messages that the executor task will process.
     pub to_executor: mpsc::Receiver<ToExecutor>,
+    /// Directory in which compiled Wasm artifacts are persisted, if any. When set, a runtime
+    /// that has already been compiled once (even in a previous run) is deserialized from this
+    /// directory instead of being recompiled from source.
+    pub compiled_cache_dir: Option<std::path::PathBuf>,
+    /// Maximum number of blocks that are verified and executed at the same time. Blocks built on
+    /// distinct forks are independent and can safely run concurrently; this bounds how many do
+    /// so at once in order to cap CPU and memory usage.
+    pub max_concurrent_executions: usize,
+    /// Host-function implementations to make available to runtimes during execution, beyond the
+    /// storage access that is always wired up.
+    pub extensions: Arc<host_extensions::Extensions>,
 }
 
 /// Runs the task itself.
-pub async fn run_executor_task(mut config: Config) {
-    // Tuple of the runtime code of the chain head and its corresponding `WasmBlob`.
-    // Used to avoid recompiling it every single time.
-    let mut wasm_blob_cache: Option<(Vec<u8>, executor::WasmBlob)> = None;
+pub async fn run_executor_task(config: Config) {
+    // Cache of compiled runtimes, keyed by the hash of their code. Shared by every concurrently
+    // running execution, so that executing blocks of sibling forks that haven't diverged in
+    // runtime code reuses the same compilation rather than recompiling on every block.
+    let wasm_blob_cache = Arc::new(Mutex::new(executor::cache::WasmBlobCache::new(
+        WASM_BLOB_CACHE_CAPACITY,
+    )));
+    let semaphore = Arc::new(Semaphore::new(config.max_concurrent_executions));
+
+    let storage = config.storage;
+    let compiled_cache_dir = Arc::new(config.compiled_cache_dir);
+    let extensions = config.extensions;
+    let tasks_executor = config.tasks_executor;
+    let mut to_executor = config.to_executor;
 
-    while let Some(event) = config.to_executor.next().await {
+    while let Some(event) = to_executor.next().await {
         match event {
             ToExecutor::Execute {
-                mut to_execute,
+                to_execute,
                 send_back,
             } => {
                 if send_back.is_canceled() {
                     continue;
                 }
 
-                let parent = config
-                    .storage
-                    .block(&to_execute.header.parent_hash)
-                    .storage()
-                    .unwrap();
-
-                // In order to avoid parsing/compiling the runtime code every single time, we
-                // maintain a cache of the `WasmBlob` of the head of the chain.
-                let runtime_wasm_blob = {
-                    let code = parent.code_key().unwrap();
-                    if wasm_blob_cache
-                        .as_ref()
-                        .map(|(c, _)| *c != code.as_ref())
-                        .unwrap_or(true)
-                    {
-                        let wasm_blob = executor::WasmBlob::from_bytes(code.as_ref()).unwrap();
-                        wasm_blob_cache = Some((code.as_ref().to_vec(), wasm_blob));
-                    }
-                    &wasm_blob_cache.as_ref().unwrap().1
-                };
-
-                let import_result =
-                    crate::block_import::verify_block(crate::block_import::Config {
-                        runtime: runtime_wasm_blob,
-                        block_header: &to_execute.header,
-                        block_body: &to_execute.extrinsics,
-                        parent_storage_get: {
-                            let parent = parent.clone();
-                            move |key: Vec<u8>| {
-                                let ret: Option<Vec<u8>> =
-                                    parent.get(&key).map(|v| v.as_ref().to_vec());
-                                async move { ret }
-                            }
-                        },
-                        parent_storage_keys_prefix: {
-                            let parent = parent.clone();
-                            move |prefix: Vec<u8>| {
-                                assert!(prefix.is_empty()); // TODO: not implemented
-                                let ret: Vec<Vec<u8>> =
-                                    parent.storage_keys().map(|v| v.as_ref().to_vec()).collect();
-                                async move { ret }
-                            }
-                        },
-                        parent_storage_next_key: {
-                            let parent = parent.clone();
-                            move |key: Vec<u8>| {
-                                let ret: Option<Vec<u8>> =
-                                    parent.next_key(&key).map(|v| v.as_ref().to_vec());
-                                async move { ret }
-                            }
-                        },
-                    })
+                let storage = storage.clone();
+                let wasm_blob_cache = wasm_blob_cache.clone();
+                let semaphore = semaphore.clone();
+                let compiled_cache_dir = compiled_cache_dir.clone();
+                let extensions = extensions.clone();
+
+                tasks_executor(Box::pin(async move {
+                    let _permit = semaphore.acquire().await;
+                    execute_one(
+                        storage,
+                        &wasm_blob_cache,
+                        compiled_cache_dir.as_deref(),
+                        extensions,
+                        to_execute,
+                        send_back,
+                    )
                     .await;
+                }));
+            }
+        }
+    }
+}
+
+/// Verifies and executes a single block, then sends the outcome back through `send_back`.
+///
+/// Blocks with distinct parents touch disjoint state and are safe to run concurrently; blocks
+/// that share a parent hash are not serialized against each other here, but still share the same
+/// compiled runtime through `wasm_blob_cache`.
+async fn execute_one(
+    storage: storage::Storage,
+    wasm_blob_cache: &Mutex<executor::cache::WasmBlobCache>,
+    compiled_cache_dir: Option<&std::path::Path>,
+    extensions: Arc<host_extensions::Extensions>,
+    mut to_execute: block::Block,
+    send_back: oneshot::Sender<Result<ExecuteSuccess, ExecuteError>>,
+) {
+    // Held for the rest of this function so that a sibling block sharing this parent can't have
+    // `storage.remove_storage` race it out from under us (see `Storage::acquire_parent`).
+    let parent = match storage.acquire_parent(&to_execute.header.parent_hash) {
+        Some(parent) => parent,
+        None => {
+            let _ = send_back.send(Err(ExecuteError::ParentNotFound));
+            return;
+        }
+    };
+
+    // In order to avoid parsing/compiling the runtime code every single time, we go
+    // through a cache keyed by the hash of the code rather than comparing the full
+    // byte slice, so that sibling forks running the same runtime share compilations.
+    let code = match parent.code_key() {
+        Some(code) => code,
+        None => {
+            let _ = send_back.send(Err(ExecuteError::RuntimeCodeMissing));
+            return;
+        }
+    };
+    let code_hash = blake2_hash(code.as_ref());
+    let runtime_wasm_blob = match get_or_compile(wasm_blob_cache, compiled_cache_dir, code_hash, code.as_ref()) {
+        Ok(wasm_blob) => wasm_blob,
+        Err(err) => {
+            let _ = send_back.send(Err(err));
+            return;
+        }
+    };
+    let runtime_wasm_blob = &*runtime_wasm_blob;
 
-                match import_result {
-                    Ok(success) => {
-                        if success.storage_top_trie_changes.contains_key(&b":code"[..]) {
-                            wasm_blob_cache = None;
-                        }
-
-                        let mut new_block_storage = (*parent).clone();
-                        for (key, value) in success.storage_top_trie_changes.iter() {
-                            if let Some(value) = value.as_ref() {
-                                new_block_storage.insert(key, value.clone())
-                            } else {
-                                new_block_storage.remove(key);
-                            }
-                        }
-                        let new_hash = to_execute.header.block_hash();
-                        // TODO: hack because our storage story is bad regarding memory
-                        config
-                            .storage
-                            .remove_storage(&to_execute.header.parent_hash);
-                        config
-                            .storage
-                            .block(&new_hash.0.into())
-                            .set_storage(new_block_storage);
-
-                        let _ = send_back.send(Ok(ExecuteSuccess {
-                            block: to_execute,
-                            storage_changes: success.storage_top_trie_changes,
-                        }));
-                    }
-                    Err(_) => panic!(), // TODO:
+    let import_result = crate::block_import::verify_block(crate::block_import::Config {
+        runtime: runtime_wasm_blob,
+        block_header: &to_execute.header,
+        block_body: &to_execute.extrinsics,
+        extensions: extensions.clone(),
+        parent_storage_get: {
+            let parent = parent.block_storage();
+            move |key: Vec<u8>| {
+                let ret: Option<Vec<u8>> = parent.get(&key).map(|v| v.as_ref().to_vec());
+                async move { ret }
+            }
+        },
+        parent_storage_keys_prefix: {
+            let parent = parent.block_storage();
+            // A single bounded `range` query over the block's `BTreeMap`, not a full scan.
+            move |prefix: Vec<u8>| {
+                let ret: Vec<Vec<u8>> = parent
+                    .storage_keys_prefix(&prefix)
+                    .map(|v| v.as_ref().to_vec())
+                    .collect();
+                async move { ret }
+            }
+        },
+        parent_storage_next_key: {
+            let parent = parent.block_storage();
+            move |prefix: Vec<u8>, key: Vec<u8>| {
+                let ret: Option<Vec<u8>> = parent.next_key_in_prefix(&prefix, &key);
+                async move { ret }
+            }
+        },
+    })
+    .await;
+
+    match import_result {
+        Ok(success) => {
+            if let Some(Some(new_code)) = success.storage_top_trie_changes.get(&b":code"[..]) {
+                // Pre-compile and insert the new runtime under its own hash rather than
+                // invalidating the whole cache, so that other forks still running the old code
+                // keep benefiting from it. A failure here doesn't affect the block we just
+                // executed, so it is only logged rather than reported back to the caller.
+                let new_code_hash = blake2_hash(new_code);
+                if let Err(err) =
+                    get_or_compile(wasm_blob_cache, compiled_cache_dir, new_code_hash, new_code)
+                {
+                    log::warn!("Failed to pre-compile new runtime after `:code` change: {:?}", err);
+                }
+            }
+
+            let mut new_block_storage = (*parent).clone();
+            for (key, value) in success.storage_top_trie_changes.iter() {
+                if let Some(value) = value.as_ref() {
+                    new_block_storage.insert(key, value.clone())
+                } else {
+                    new_block_storage.remove(key);
                 }
             }
+            let new_hash = to_execute.header.block_hash();
+            // TODO: hack because our storage story is bad regarding memory
+            storage.remove_storage(&to_execute.header.parent_hash);
+            storage.block(&new_hash.0.into()).set_storage(new_block_storage);
+
+            let _ = send_back.send(Ok(ExecuteSuccess {
+                block: to_execute,
+                storage_changes: success.storage_top_trie_changes,
+            }));
+        }
+        // Surfaced as its own variant, rather than folded into `VerificationFailed` below, so
+        // that callers can distinguish a bad block from a node that disagrees with the rest of
+        // the network about the state transition function.
+        Err(crate::block_import::Error::StateRootMismatch { .. }) => {
+            let _ = send_back.send(Err(ExecuteError::StateRootMismatch));
+        }
+        Err(error) => {
+            let _ = send_back.send(Err(ExecuteError::VerificationFailed(format!("{:?}", error))));
+        }
+    }
+}
+
+/// A counting semaphore usable from asynchronous code, backed by a bounded channel acting as a
+/// pool of permits.
+struct Semaphore {
+    acquire_tx: mpsc::Sender<()>,
+    acquire_rx: futures::lock::Mutex<mpsc::Receiver<()>>,
+}
+
+impl Semaphore {
+    /// Creates a new semaphore with `permits` available permits.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `permits` is 0.
+    fn new(permits: usize) -> Self {
+        assert!(permits > 0);
+        let (mut acquire_tx, acquire_rx) = mpsc::channel(permits);
+        for _ in 0..permits {
+            acquire_tx.try_send(()).unwrap();
+        }
+        Semaphore {
+            acquire_tx,
+            acquire_rx: futures::lock::Mutex::new(acquire_rx),
+        }
+    }
+
+    /// Waits for a permit to become available, returning a guard that releases it on drop.
+    async fn acquire(self: &Arc<Self>) -> SemaphorePermit {
+        self.acquire_rx.lock().await.next().await.unwrap();
+        SemaphorePermit {
+            semaphore: self.clone(),
         }
     }
+}
+
+/// Permit obtained from [`Semaphore::acquire`]. Releases the permit back to the semaphore when
+/// dropped.
+struct SemaphorePermit {
+    semaphore: Arc<Semaphore>,
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        let _ = self.semaphore.acquire_tx.clone().try_send(());
+    }
+}
+
+/// Computes the blake2b-256 hash of a slice of bytes.
+fn blake2_hash(data: &[u8]) -> H256 {
+    H256::from_slice(blake2_rfc::blake2b::blake2b(32, &[], data).as_bytes())
+}
+
+/// Returns the compiled [`executor::WasmBlob`] for `code_hash`, compiling (or loading from disk)
+/// and inserting it into `wasm_blob_cache` on a miss.
+///
+/// `cache`'s lock is only held for the cache lookup and the final insertion, not across the
+/// compilation (or disk load) itself: `compile_or_load` can take a while, and holding the lock for that long would
+/// serialize every concurrently-executing block on it. On a race between two callers missing the
+/// cache for the same `code_hash`, both compile and the second insertion simply overwrites the
+/// first with an equivalent blob.
+fn get_or_compile(
+    cache: &Mutex<executor::cache::WasmBlobCache>,
+    compiled_cache_dir: Option<&std::path::Path>,
+    code_hash: H256,
+    code: &[u8],
+) -> Result<Arc<executor::WasmBlob>, ExecuteError> {
+    if let Some(wasm_blob) = cache.lock().unwrap().get(code_hash) {
+        return Ok(wasm_blob);
+    }
+
+    let wasm_blob = compile_or_load(compiled_cache_dir, code_hash, code)?;
+    cache.lock().unwrap().insert(code_hash, wasm_blob.clone());
+    Ok(wasm_blob)
+}
+
+/// Obtains a compiled [`executor::WasmBlob`] for `code`, loading it from `compiled_cache_dir` if
+/// a matching artifact is present there, and compiling it from source (persisting the result for
+/// next time) otherwise.
+fn compile_or_load(
+    compiled_cache_dir: Option<&std::path::Path>,
+    code_hash: H256,
+    code: &[u8],
+) -> Result<Arc<executor::WasmBlob>, ExecuteError> {
+    if let Some(dir) = compiled_cache_dir {
+        if let Some(wasm_blob) = executor::disk_cache::load(dir, code_hash) {
+            return Ok(Arc::new(wasm_blob));
+        }
+    }
+
+    let wasm_blob = executor::WasmBlob::from_bytes(code)
+        .map_err(|err| ExecuteError::CompileFailed(format!("{:?}", err)))?;
+
+    if let Some(dir) = compiled_cache_dir {
+        if let Err(err) = executor::disk_cache::store(dir, code_hash, &wasm_blob) {
+            log::warn!("Failed to persist compiled runtime artifact: {}", err);
+        }
+    }
+
+    Ok(Arc::new(wasm_blob))
 }
\ No newline at end of file
@@ -0,0 +1,356 @@
+//! Storage of the state of each block of the chain.
+//!
+//! Each block known to the node has its own [`BlockStorage`], a full key-value view of its
+//! top trie. Because every block currently keeps its own copy of the full state (see the
+//! `TODO` in `service::executor_task`), large values such as runtime code or big contract blobs
+//! are compressed before being stored, so that the cost of keeping around many forks' worth of
+//! state stays bounded.
+
+use alloc::sync::Arc;
+use primitive_types::H256;
+use std::{
+    collections::BTreeMap,
+    sync::RwLock,
+};
+
+/// Key under which the runtime code is stored in the top trie.
+const CODE_KEY: &[u8] = b":code";
+
+/// Values at least this large are zstd-compressed before being stored.
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 3 * 1024;
+
+/// Tag prepended to a stored value indicating it is stored as-is.
+const TAG_RAW: u8 = 0;
+/// Tag prepended to a stored value indicating it is zstd-compressed.
+const TAG_COMPRESSED: u8 = 1;
+
+/// A block's storage together with bookkeeping for [`Storage::acquire_parent`].
+struct BlockSlot {
+    storage: Arc<BlockStorage>,
+    /// Number of [`ParentGuard`]s currently alive for this block. Its storage isn't actually
+    /// dropped while this is non-zero, even if [`Storage::remove_storage`] is called.
+    in_flight: usize,
+    /// Set by [`Storage::remove_storage`] if it was called while `in_flight` was still non-zero;
+    /// the slot is then actually removed once the last guard is dropped instead.
+    removal_pending: bool,
+}
+
+/// Storage of the state of every block known to the node.
+#[derive(Clone)]
+pub struct Storage {
+    blocks: Arc<RwLock<BTreeMap<H256, BlockSlot>>>,
+    /// See [`DEFAULT_COMPRESSION_THRESHOLD`]. Stored here so that every [`BlockStorage`] created
+    /// through this [`Storage`] shares the same threshold.
+    compression_threshold: usize,
+}
+
+impl Storage {
+    /// Creates a new empty [`Storage`], compressing values of at least
+    /// [`DEFAULT_COMPRESSION_THRESHOLD`] bytes.
+    pub fn new() -> Self {
+        Storage::with_compression_threshold(DEFAULT_COMPRESSION_THRESHOLD)
+    }
+
+    /// Creates a new empty [`Storage`], compressing values of at least `compression_threshold`
+    /// bytes.
+    pub fn with_compression_threshold(compression_threshold: usize) -> Self {
+        Storage {
+            blocks: Arc::new(RwLock::new(BTreeMap::new())),
+            compression_threshold,
+        }
+    }
+
+    /// Returns a handle to the storage of the block with the given hash.
+    pub fn block(&self, hash: &H256) -> BlockEntry {
+        BlockEntry {
+            storage: self,
+            hash: *hash,
+        }
+    }
+
+    /// Returns the storage of the block with the given hash, if known, marking it as used as a
+    /// parent by an in-flight execution for as long as the returned [`ParentGuard`] stays alive.
+    ///
+    /// Blocks built on the same parent (the ordinary case of two candidates at the same height)
+    /// are executed concurrently; without this, whichever finishes first would call
+    /// [`Storage::remove_storage`] on the shared parent and the other could observe it gone
+    /// before it even started. [`Storage::remove_storage`] on a hash with an outstanding guard is
+    /// deferred instead of taking effect immediately.
+    pub fn acquire_parent(&self, hash: &H256) -> Option<ParentGuard> {
+        let mut blocks = self.blocks.write().unwrap();
+        let slot = blocks.get_mut(hash)?;
+        slot.in_flight += 1;
+        let storage = slot.storage.clone();
+        Some(ParentGuard {
+            storage: self.clone(),
+            hash: *hash,
+            block_storage: storage,
+        })
+    }
+
+    /// Removes the storage of the block with the given hash, if any and if it isn't currently
+    /// held by a live [`ParentGuard`]; otherwise the removal takes effect once the last such
+    /// guard is dropped.
+    pub fn remove_storage(&self, hash: &H256) {
+        let mut blocks = self.blocks.write().unwrap();
+        match blocks.get_mut(hash) {
+            Some(slot) if slot.in_flight > 0 => slot.removal_pending = true,
+            _ => {
+                blocks.remove(hash);
+            }
+        }
+    }
+
+    /// Creates a new empty [`BlockStorage`] that compresses values the same way every other
+    /// block of this [`Storage`] does.
+    pub fn empty_block_storage(&self) -> BlockStorage {
+        BlockStorage::empty(self.compression_threshold)
+    }
+
+    /// Releases the in-flight marker held by a [`ParentGuard`] for `hash`, actually removing the
+    /// block's storage if it was left pending removal and this was the last guard.
+    fn release_parent(&self, hash: &H256) {
+        let mut blocks = self.blocks.write().unwrap();
+        if let Some(slot) = blocks.get_mut(hash) {
+            slot.in_flight -= 1;
+            if slot.in_flight == 0 && slot.removal_pending {
+                blocks.remove(hash);
+            }
+        }
+    }
+}
+
+/// Handle to the storage of a specific block, obtained through [`Storage::block`].
+pub struct BlockEntry<'a> {
+    storage: &'a Storage,
+    hash: H256,
+}
+
+impl<'a> BlockEntry<'a> {
+    /// Returns the storage of this block, if known.
+    pub fn storage(&self) -> Option<Arc<BlockStorage>> {
+        self.storage
+            .blocks
+            .read()
+            .unwrap()
+            .get(&self.hash)
+            .map(|slot| slot.storage.clone())
+    }
+
+    /// Sets the storage of this block.
+    pub fn set_storage(&self, storage: BlockStorage) {
+        self.storage.blocks.write().unwrap().insert(
+            self.hash,
+            BlockSlot {
+                storage: Arc::new(storage),
+                in_flight: 0,
+                removal_pending: false,
+            },
+        );
+    }
+}
+
+/// Guard returned by [`Storage::acquire_parent`], keeping that hash's storage alive (even across
+/// a concurrent [`Storage::remove_storage`] call) until dropped.
+pub struct ParentGuard {
+    storage: Storage,
+    hash: H256,
+    block_storage: Arc<BlockStorage>,
+}
+
+impl ParentGuard {
+    /// Returns a cheap clone of the underlying [`Arc<BlockStorage>`], independent of this guard's
+    /// own lifetime (the parent is only released when the guard itself is dropped).
+    pub fn block_storage(&self) -> Arc<BlockStorage> {
+        self.block_storage.clone()
+    }
+}
+
+impl core::ops::Deref for ParentGuard {
+    type Target = BlockStorage;
+
+    fn deref(&self) -> &BlockStorage {
+        &self.block_storage
+    }
+}
+
+impl Drop for ParentGuard {
+    fn drop(&mut self) {
+        self.storage.release_parent(&self.hash);
+    }
+}
+
+/// Full key-value view of a block's top trie.
+///
+/// Values are stored with a one-byte tag indicating whether they are compressed, so that
+/// [`BlockStorage::get`] can transparently decompress them; callers never see the tag.
+#[derive(Clone)]
+pub struct BlockStorage {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+    compression_threshold: usize,
+}
+
+impl BlockStorage {
+    /// Creates a new empty [`BlockStorage`], compressing values of at least
+    /// `compression_threshold` bytes.
+    ///
+    /// Prefer [`Storage::empty_block_storage`] when creating the storage of a block that belongs
+    /// to a [`Storage`], so that it shares that `Storage`'s configured threshold.
+    pub fn empty(compression_threshold: usize) -> Self {
+        BlockStorage {
+            entries: BTreeMap::new(),
+            compression_threshold,
+        }
+    }
+
+    /// Returns the value of the `:code` key, if any.
+    pub fn code_key(&self) -> Option<Vec<u8>> {
+        self.get(CODE_KEY)
+    }
+
+    /// Returns the value associated with `key`, if any, transparently decompressing it.
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.entries.get(key).map(|encoded| decode(encoded))
+    }
+
+    /// Returns an iterator over all the keys currently in storage.
+    pub fn storage_keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.entries.keys()
+    }
+
+    /// Returns an iterator over the keys starting with `prefix`, without walking the rest of the
+    /// keyspace: this is a single bounded `range` query over the underlying ordered map.
+    pub fn storage_keys_prefix<'a>(&'a self, prefix: &[u8]) -> impl Iterator<Item = &'a Vec<u8>> {
+        self.entries.range(prefix_range(prefix)).map(|(k, _)| k)
+    }
+
+    /// Returns the lexicographically next key strictly after `key`, if any.
+    pub fn next_key(&self, key: &[u8]) -> Option<Vec<u8>> {
+        use core::ops::Bound;
+        self.entries
+            .range((Bound::Excluded(key.to_vec()), Bound::Unbounded))
+            .next()
+            .map(|(k, _)| k.clone())
+    }
+
+    /// Returns the lexicographically next key at or after `key` that still starts with `prefix`,
+    /// if any, without walking the rest of the keyspace.
+    ///
+    /// `key` isn't assumed to itself start with `prefix` (it may be `prefix` itself, as passed by
+    /// a runtime starting an iteration), so the effective lower bound is whichever of the two
+    /// sorts last.
+    pub fn next_key_in_prefix(&self, prefix: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+        use core::ops::Bound;
+        let (_, upper) = prefix_range(prefix);
+        let lower = core::cmp::max(prefix.to_vec(), key.to_vec());
+        self.entries
+            .range((Bound::Included(lower), upper))
+            .next()
+            .map(|(k, _)| k.clone())
+    }
+
+    /// Inserts or overwrites the value associated with `key`, compressing it first if it is at
+    /// least `self.compression_threshold` bytes.
+    pub fn insert(&mut self, key: &[u8], value: Vec<u8>) {
+        self.entries.insert(key.to_vec(), encode(value, self.compression_threshold));
+    }
+
+    /// Removes the value associated with `key`, if any.
+    pub fn remove(&mut self, key: &[u8]) {
+        self.entries.remove(key);
+    }
+}
+
+/// Returns the `(start, end)` bounds of the range of keys starting with `prefix`, suitable for
+/// `BTreeMap::range`, so that a prefix scan is a single bounded lookup instead of a full-keyspace
+/// walk.
+fn prefix_range(prefix: &[u8]) -> (core::ops::Bound<Vec<u8>>, core::ops::Bound<Vec<u8>>) {
+    use core::ops::Bound;
+    let upper = prefix_upper_bound(prefix)
+        .map(Bound::Excluded)
+        .unwrap_or(Bound::Unbounded);
+    (Bound::Included(prefix.to_vec()), upper)
+}
+
+/// Returns the lexicographically smallest byte string that is strictly greater than every byte
+/// string starting with `prefix`, or `None` if no such bound exists (`prefix` is empty or made
+/// entirely of `0xff` bytes), in which case the range is unbounded above.
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper = prefix.to_vec();
+    while let Some(&last) = upper.last() {
+        if last == 0xff {
+            upper.pop();
+        } else {
+            *upper.last_mut().unwrap() += 1;
+            return Some(upper);
+        }
+    }
+    None
+}
+
+/// Encodes `value` into its stored representation: a one-byte tag followed by either the value
+/// itself or its zstd-compressed form.
+fn encode(value: Vec<u8>, compression_threshold: usize) -> Vec<u8> {
+    if value.len() >= compression_threshold {
+        if let Ok(compressed) = zstd::stream::encode_all(&value[..], 0) {
+            let mut out = Vec::with_capacity(compressed.len() + 1);
+            out.push(TAG_COMPRESSED);
+            out.extend_from_slice(&compressed);
+            return out;
+        }
+    }
+
+    let mut out = Vec::with_capacity(value.len() + 1);
+    out.push(TAG_RAW);
+    out.extend_from_slice(&value);
+    out
+}
+
+/// Reverses [`encode`].
+fn decode(encoded: &[u8]) -> Vec<u8> {
+    match encoded.split_first() {
+        Some((&TAG_RAW, rest)) => rest.to_vec(),
+        Some((&TAG_COMPRESSED, rest)) => {
+            zstd::stream::decode_all(rest).expect("corrupted compressed storage entry")
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockStorage;
+
+    fn storage_with(keys: &[&[u8]]) -> BlockStorage {
+        let mut storage = BlockStorage::empty(1024);
+        for key in keys {
+            storage.insert(key, b"value".to_vec());
+        }
+        storage
+    }
+
+    #[test]
+    fn next_key_in_prefix_skips_to_the_right_prefix() {
+        // Sparse keyspace where the key immediately following `key` doesn't match `prefix`, but
+        // a later one does.
+        let storage = storage_with(&[b"a0", b"az", b"b0", b"b1"]);
+        assert_eq!(
+            storage.next_key_in_prefix(b"b", b"a0"),
+            Some(b"b0".to_vec())
+        );
+    }
+
+    #[test]
+    fn next_key_in_prefix_returns_none_past_the_prefix() {
+        let storage = storage_with(&[b"a0", b"az", b"b0", b"b1"]);
+        assert_eq!(storage.next_key_in_prefix(b"b", b"b2"), None);
+        assert_eq!(storage.next_key_in_prefix(b"c", b"c"), None);
+    }
+
+    #[test]
+    fn storage_keys_prefix_only_returns_matching_keys() {
+        let storage = storage_with(&[b"a0", b"az", b"b0", b"b1"]);
+        let keys: Vec<Vec<u8>> = storage.storage_keys_prefix(b"b").cloned().collect();
+        assert_eq!(keys, vec![b"b0".to_vec(), b"b1".to_vec()]);
+    }
+}